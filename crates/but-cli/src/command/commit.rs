@@ -1,13 +1,16 @@
 use crate::command::discard_change::IndicesOrHeaders;
 use crate::command::{debug_print, indices_or_headers_to_hunk_headers, path_to_rela_path};
-use anyhow::bail;
+use anyhow::{Context, bail};
 use but_core::TreeChange;
 use but_workspace::commit_engine::{
-    DiffSpec, ReferenceFrame, StackSegmentId, create_commit_and_update_refs,
+    DiffSpec, HunkHeader, ReferenceFrame, StackSegmentId, create_commit_and_update_refs,
 };
 use gitbutler_project::Project;
 use gitbutler_stack::{VirtualBranchesHandle, VirtualBranchesState};
-use std::path::Path;
+use std::collections::HashMap;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 #[allow(clippy::too_many_arguments)]
 pub fn commit(
@@ -15,6 +18,9 @@ pub fn commit(
     project: Option<Project>,
     message: Option<&str>,
     amend: bool,
+    absorb: bool,
+    no_verify: bool,
+    gpg_sign: Option<signing::Override>,
     parent_revspec: Option<&str>,
     stack_segment_ref: Option<&str>,
     workspace_tip: Option<&str>,
@@ -22,7 +28,29 @@ pub fn commit(
     previous_rela_path: Option<&Path>,
     headers: Option<&[u32]>,
     diff_spec: Option<Vec<DiffSpec>>,
+    patch: Option<&Path>,
 ) -> anyhow::Result<()> {
+    if absorb {
+        let project = project
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("`--absorb` requires a project"))?;
+        if amend || message.is_some() {
+            bail!("`--absorb` picks its own targets, it can't be combined with --amend or a message");
+        }
+        if current_rela_path.is_some() || headers.is_some() || diff_spec.is_some() || patch.is_some() {
+            bail!("`--absorb` always distributes the entire worktree diff, it can't be scoped to a single path");
+        }
+        let signer = signing::resolve(&repo, gpg_sign)?;
+        let mut guard = project.exclusive_worktree_access();
+        return debug_print(absorb_worktree_changes_into_stack(
+            &repo,
+            project,
+            no_verify,
+            signer.as_ref(),
+            guard.write_permission(),
+        )?);
+    }
+
     if message.is_none() && !amend {
         bail!("Need a message when creating a new commit");
     }
@@ -32,12 +60,12 @@ pub fn commit(
         .map(|id| id.map(|id| id.detach()))
         .transpose()?;
 
-    let changes = match (current_rela_path, previous_rela_path, headers, diff_spec) {
-        (None, None, None, Some(diff_spec)) => diff_spec,
-        (None, None, None, None) => {
+    let changes = match (current_rela_path, previous_rela_path, headers, diff_spec, patch) {
+        (None, None, None, Some(diff_spec), None) => diff_spec,
+        (None, None, None, None, None) => {
             to_whole_file_diffspec(but_core::diff::worktree_changes(&repo)?.changes)
         }
-        (Some(current_path), previous_path, Some(headers), None) => {
+        (Some(current_path), previous_path, Some(headers), None, None) => {
             let path = path_to_rela_path(current_path)?;
             let previous_path = previous_path.map(path_to_rela_path).transpose()?;
             let hunk_headers = indices_or_headers_to_hunk_headers(
@@ -53,22 +81,61 @@ pub fn commit(
                 hunk_headers,
             }]
         }
+        (None, None, None, None, Some(patch_path)) => {
+            let text = if patch_path == Path::new("-") {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(patch_path)
+                    .with_context(|| format!("failed to read patch at {}", patch_path.display()))?
+            };
+            unified_diff::parse(&repo, &text)?
+        }
         _ => unreachable!("BUG: specifying this shouldn't be possible"),
     };
+    let signer = signing::resolve(&repo, gpg_sign)?;
+
     if let Some(project) = project.as_ref() {
+        let (stack_segment, parent_commit_id) = if amend {
+            (None, Some(parent_id.unwrap_or(repo.head_id()?.detach())))
+        } else {
+            get_stack_segment_info(&repo, stack_segment_ref, parent_id, project)?
+        };
+
+        if !no_verify {
+            // The base the hook's preview tree is built on: for a new commit that's
+            // simply the parent's tree, but for an amend it's the *amend target's*
+            // parent tree (the target itself is being replaced, not built upon).
+            let preview_base_tree_id = if amend {
+                amend_target_parent_tree_id(
+                    &repo,
+                    parent_commit_id.expect("amend always resolves a target commit"),
+                )?
+            } else {
+                tree_id_of(&repo, parent_commit_id)?
+            };
+            hooks::run_pre_commit(&repo, project, preview_base_tree_id, &changes)?;
+        }
+        let message = message
+            .map(|message| {
+                if no_verify {
+                    Ok(message.to_owned())
+                } else {
+                    hooks::run_commit_msg_hooks(project, message)
+                }
+            })
+            .transpose()?;
+
         let destination = if amend {
             if message.is_some() {
                 bail!("Messages aren't used when amending");
             }
-            let parent_id = parent_id.unwrap_or(repo.head_id()?.detach());
-            but_workspace::commit_engine::Destination::AmendCommit(parent_id)
+            but_workspace::commit_engine::Destination::AmendCommit(
+                parent_commit_id.expect("amend always resolves a target commit"),
+            )
         } else {
-            let (stack_segment, parent_commit_id) =
-                get_stack_segment_info(&repo, stack_segment_ref, parent_id, project)?;
-
             but_workspace::commit_engine::Destination::NewCommit {
                 parent_commit_id,
-                message: message.unwrap_or_default().to_owned(),
+                message: message.unwrap_or_default(),
                 stack_segment,
             }
         };
@@ -82,9 +149,13 @@ pub fn commit(
                 None,
                 changes,
                 0, /* context-lines */
+                signer.as_ref(),
                 guard.write_permission(),
             )?,
         )?;
+        if !no_verify {
+            hooks::run_post_commit(project)?;
+        }
     } else {
         let destination = if amend {
             if message.is_some() {
@@ -118,6 +189,7 @@ pub fn commit(
             None,
             changes,
             0,
+            signer.as_ref(),
         )?)?;
     }
     Ok(())
@@ -188,6 +260,24 @@ fn normalize_stack_segment_ref(
     gix::refs::FullName::try_from(full_name)
 }
 
+/// The tree id `commit_id` resolves to, or the empty tree if there is no commit (a
+/// brand new repository with no `HEAD` yet).
+fn tree_id_of(repo: &gix::Repository, commit_id: Option<gix::ObjectId>) -> anyhow::Result<gix::ObjectId> {
+    match commit_id {
+        Some(id) => Ok(repo.find_commit(id)?.tree_id()?.detach()),
+        None => Ok(repo.object_hash().empty_tree()),
+    }
+}
+
+/// The tree a commit's replacement content is built on top of when amending it - i.e.
+/// the tree of its own parent (or the empty tree, if it's a root commit). This is the
+/// base an amend's hunks apply against, since the commit being amended is replaced
+/// wholesale rather than built upon.
+fn amend_target_parent_tree_id(repo: &gix::Repository, commit_id: gix::ObjectId) -> anyhow::Result<gix::ObjectId> {
+    let parent = repo.find_commit(commit_id)?.parent_ids().next().map(|id| id.detach());
+    tree_id_of(repo, parent)
+}
+
 fn to_whole_file_diffspec(changes: Vec<TreeChange>) -> Vec<DiffSpec> {
     changes
         .into_iter()
@@ -198,3 +288,1179 @@ fn to_whole_file_diffspec(changes: Vec<TreeChange>) -> Vec<DiffSpec> {
         })
         .collect()
 }
+
+/// Distribute every hunk in the worktree diff into the stack commit that last touched
+/// its lines, the moral equivalent of `git absorb`. Hunks that don't match any commit,
+/// or that don't commute past the commits sitting between their target and `HEAD`,
+/// are left in the worktree rather than forced into a normal commit. Unless `no_verify`
+/// is set, `pre-commit` runs before each individual amend; `post-commit` runs once for
+/// the whole batch afterwards rather than once per amend, since absorb's amends aren't
+/// separately user-visible commits the way repeated `commit --amend` invocations are.
+fn absorb_worktree_changes_into_stack(
+    repo: &gix::Repository,
+    project: &Project,
+    no_verify: bool,
+    signer: Option<&but_workspace::commit_engine::Signer>,
+    perm: &mut gitbutler_project::access::WriteWorkspace,
+) -> anyhow::Result<serde_json::Value> {
+    let worktree = but_core::diff::worktree_changes(repo)?;
+    let stacks = VirtualBranchesHandle::new(project.gb_dir()).list_stacks_in_workspace()?;
+
+    // Keyed by (stack_index, position_from_oldest) rather than a commit id: amending
+    // an older commit rewrites the oid of every newer commit in the same stack, so an
+    // id captured during this scan would already be stale by the time we get around
+    // to amending it below. Position survives an amend; raw oids don't.
+    let mut per_commit_hunks: HashMap<(usize, usize), Vec<DiffSpec>> = HashMap::new();
+    let mut leftover = Vec::new();
+
+    for change in worktree.changes {
+        let path = change.path.clone();
+        let previous_path = change.previous_path().map(ToOwned::to_owned);
+        let all_hunks = indices_or_headers_to_hunk_headers(repo, None, &path, previous_path.as_ref())?;
+
+        for hunk in all_hunks {
+            match find_absorb_target(repo, &stacks, &path, previous_path.as_ref(), hunk)? {
+                Some(target) => {
+                    let spec = per_commit_hunks.entry(target).or_default();
+                    match spec.iter_mut().find(|s: &&mut DiffSpec| {
+                        s.path == path && s.previous_path == previous_path
+                    }) {
+                        Some(existing) => existing.hunk_headers.push(hunk),
+                        None => spec.push(DiffSpec {
+                            previous_path: previous_path.clone(),
+                            path: path.clone(),
+                            hunk_headers: vec![hunk],
+                        }),
+                    }
+                }
+                None => leftover.push((path.clone(), previous_path.clone(), hunk)),
+            }
+        }
+    }
+
+    // Amend oldest-first within each stack (the order stacks are visited in doesn't
+    // matter - they're independent). Re-resolve each target's current commit id from
+    // the stack's live state right before amending it, since earlier amends in this
+    // loop may have already rewritten the oids of commits still waiting their turn.
+    let mut ordered: Vec<_> = per_commit_hunks.into_iter().collect();
+    ordered.sort_by_key(|((stack_index, position), _)| (*stack_index, *position));
+
+    let mut amended = Vec::new();
+    for ((stack_index, position), changes) in ordered {
+        let stack = &stacks[stack_index];
+        let current_ids: Vec<gix::ObjectId> = stack
+            .mutable_commit_ids_newest_first(repo)?
+            .into_iter()
+            .rev()
+            .collect();
+        let commit_id = *current_ids.get(position).ok_or_else(|| {
+            anyhow::anyhow!("stack commit at position {position} disappeared while absorbing")
+        })?;
+
+        if !no_verify {
+            let parent_tree_id = amend_target_parent_tree_id(repo, commit_id)?;
+            hooks::run_pre_commit(repo, project, parent_tree_id, &changes)?;
+        }
+        let out = but_workspace::commit_engine::create_commit_and_update_refs_with_project(
+            repo,
+            project,
+            None,
+            but_workspace::commit_engine::Destination::AmendCommit(commit_id),
+            None,
+            changes,
+            0, /* context-lines */
+            signer,
+            perm,
+        )?;
+        amended.push(out);
+    }
+
+    if !leftover.is_empty() {
+        log::debug!(
+            "{} hunk(s) didn't commute into any stack commit and were left in the worktree",
+            leftover.len()
+        );
+    }
+    if !no_verify && !amended.is_empty() {
+        hooks::run_post_commit(project)?;
+    }
+
+    Ok(serde_json::json!({ "amended_commits": amended.len(), "left_in_worktree": leftover.len() }))
+}
+
+/// Find the newest mutable stack commit whose patch's new-file range fully contains
+/// `hunk`'s old-file range, provided `hunk` commutes past every commit between that
+/// target and the workspace tip (i.e. none of the intervening commits touch overlapping
+/// lines once line-offset drift from later hunks is accounted for). A commit whose
+/// patch only partially overlaps the hunk ends the search with `None`; a commit whose
+/// ranges are merely adjacent to the hunk is treated as disjoint, not as a target.
+/// Returns `None` if no commit qualifies, leaving the hunk in the worktree.
+///
+/// The result identifies the target by `(stack_index, position_from_oldest)` rather
+/// than its current commit id - callers amend oldest-first, and an id captured here
+/// would be stale by the time a later, older target's turn comes up.
+fn find_absorb_target(
+    repo: &gix::Repository,
+    stacks: &[gitbutler_stack::Stack],
+    path: &gix::path::RelaPath,
+    previous_path: Option<&gix::path::RelaPath>,
+    hunk: HunkHeader,
+) -> anyhow::Result<Option<(usize, usize)>> {
+    for (stack_index, stack) in stacks.iter().enumerate() {
+        // Resets per stack: two simultaneously-applied stacks can touch the same
+        // file, and the line-offset drift accumulated walking stack A's history has
+        // nothing to do with stack B's unrelated commits.
+        let mut offset: i64 = 0;
+        let oldest_first: Vec<gix::ObjectId> = stack
+            .mutable_commit_ids_newest_first(repo)?
+            .into_iter()
+            .rev()
+            .collect();
+        for (position, &commit_id) in oldest_first.iter().enumerate().rev() {
+            let touched = commit_touches_lines(repo, commit_id, path, previous_path, &hunk, offset)?;
+            match touched {
+                Touch::Target => return Ok(Some((stack_index, position))),
+                Touch::Overlap => return Ok(None),
+                Touch::Disjoint(delta) => offset += delta,
+            }
+        }
+    }
+    Ok(None)
+}
+
+enum Touch {
+    /// This commit's patch is the hunk's absorb target.
+    Target,
+    /// This commit touches overlapping lines but isn't the newest owner - the hunk
+    /// can't safely commute past it.
+    Overlap,
+    /// This commit doesn't intersect the hunk; carries a line-offset delta to apply
+    /// to the hunk's range before checking the next (older) commit.
+    Disjoint(i64),
+}
+
+fn commit_touches_lines(
+    repo: &gix::Repository,
+    commit_id: gix::ObjectId,
+    path: &gix::path::RelaPath,
+    previous_path: Option<&gix::path::RelaPath>,
+    hunk: &HunkHeader,
+    offset: i64,
+) -> anyhow::Result<Touch> {
+    let patch = but_core::diff::commit_patch(repo, commit_id)?;
+    let text = String::from_utf8_lossy(&patch);
+    let commit_hunks = unified_diff::hunks_for_path(&text, path, previous_path);
+    Ok(classify_touch(hunk, offset, &commit_hunks))
+}
+
+/// The pure line-range comparison behind [`commit_touches_lines`]: does `hunk` (shifted
+/// by `offset`) land fully inside one of `commit_hunks`' new-file ranges (`Target`),
+/// partially overlap one (`Overlap`, ambiguous ownership), or sit clear of all of them
+/// (`Disjoint`, carrying the line-count delta needed to check the next, older commit)?
+fn classify_touch(hunk: &HunkHeader, offset: i64, commit_hunks: &[HunkHeader]) -> Touch {
+    if commit_hunks.is_empty() {
+        return Touch::Disjoint(0);
+    }
+
+    // `hunk`'s range is expressed against the tree `commit` produced (its new side);
+    // that's exactly the coordinate space `commit_hunks`' new-file ranges live in, once
+    // shifted by the drift we've accumulated walking past newer disjoint commits.
+    let hunk_start = (hunk.old_start as i64 + offset).max(0) as u32;
+    let hunk_end = hunk_start + hunk.old_lines.max(1);
+
+    let mut contained = false;
+    let mut overlaps = false;
+    let mut delta = 0i64;
+    for commit_hunk in commit_hunks {
+        let c_start = commit_hunk.new_start;
+        let c_end = c_start + commit_hunk.new_lines.max(1);
+        if hunk_start >= c_start && hunk_end <= c_end {
+            contained = true;
+        } else if hunk_start < c_end && c_start < hunk_end {
+            overlaps = true;
+        } else if c_end <= hunk_start {
+            // Entirely before our hunk: its net line-count change shifts `hunk`'s
+            // position once we step back into this commit's parent tree.
+            delta += commit_hunk.old_lines as i64 - commit_hunk.new_lines as i64;
+        }
+    }
+
+    if contained {
+        Touch::Target
+    } else if overlaps {
+        Touch::Overlap
+    } else {
+        Touch::Disjoint(delta)
+    }
+}
+
+#[cfg(test)]
+mod absorb_tests {
+    use super::*;
+
+    fn hunk(old_start: u32, old_lines: u32, new_start: u32, new_lines: u32) -> HunkHeader {
+        HunkHeader {
+            old_start,
+            old_lines,
+            new_start,
+            new_lines,
+        }
+    }
+
+    #[test]
+    fn fully_contained_hunk_is_the_target() {
+        let worktree_hunk = hunk(12, 2, 0, 0);
+        let commit_hunks = [hunk(8, 3, 10, 6)];
+        assert!(matches!(
+            classify_touch(&worktree_hunk, 0, &commit_hunks),
+            Touch::Target
+        ));
+    }
+
+    #[test]
+    fn partially_overlapping_hunk_is_ambiguous() {
+        let worktree_hunk = hunk(14, 4, 0, 0);
+        let commit_hunks = [hunk(8, 3, 10, 6)];
+        assert!(matches!(
+            classify_touch(&worktree_hunk, 0, &commit_hunks),
+            Touch::Overlap
+        ));
+    }
+
+    #[test]
+    fn disjoint_hunk_carries_the_line_delta() {
+        // Commit inserted 2 net lines (old 3 -> new 5) entirely before our hunk.
+        let worktree_hunk = hunk(20, 1, 0, 0);
+        let commit_hunks = [hunk(8, 3, 10, 5)];
+        match classify_touch(&worktree_hunk, 0, &commit_hunks) {
+            Touch::Disjoint(delta) => assert_eq!(delta, -2),
+            Touch::Target | Touch::Overlap => panic!("expected Disjoint"),
+        }
+    }
+
+    #[test]
+    fn no_hunks_for_the_path_is_disjoint_with_no_delta() {
+        assert!(matches!(
+            classify_touch(&hunk(1, 1, 0, 0), 0, &[]),
+            Touch::Disjoint(0)
+        ));
+    }
+}
+
+/// Runs the repository's client-side hook scripts (`pre-commit`, `prepare-commit-msg`,
+/// `commit-msg`, `post-commit`) around a project-backed `commit`, mirroring what `git
+/// commit` itself would fire. Disabled entirely by `--no-verify`.
+mod hooks {
+    use super::*;
+
+    /// Runs `.git/hooks/pre-commit` with `GIT_INDEX_FILE` pointed at a scratch index
+    /// built from the tree `changes` would produce on top of `parent_tree_id` (the
+    /// actually-resolved destination - the new commit's resolved parent, such as the
+    /// target stack segment's tip, or the amend target's parent), so the hook sees
+    /// exactly the content the CLI is about to commit rather than whatever happens to
+    /// be in the real index (which, for a virtual-branch commit, is usually unrelated
+    /// to the hunks actually selected). Aborts the commit if the hook exits non-zero.
+    pub(super) fn run_pre_commit(
+        repo: &gix::Repository,
+        project: &Project,
+        parent_tree_id: gix::ObjectId,
+        changes: &[DiffSpec],
+    ) -> anyhow::Result<()> {
+        let Some(hook) = find_hook(project, "pre-commit") else {
+            return Ok(());
+        };
+
+        let tree_id = but_workspace::commit_engine::preview_tree_id(repo, parent_tree_id, changes, 0)
+            .context("failed to build the pre-commit preview tree")?;
+        let index = gix::index::File::from_state(
+            gix::index::State::from_tree(&tree_id, repo)
+                .context("failed to build a scratch index from the pre-commit preview tree")?,
+            project.path.join(".git/index"),
+        );
+
+        let scratch_index = tempfile::NamedTempFile::new_in(project.gb_dir())
+            .context("failed to create scratch index for pre-commit hook")?;
+        index
+            .write_to(scratch_index.path(), gix::index::write::Options::default())
+            .context("failed to write the scratch index for the pre-commit hook")?;
+
+        let status = Command::new(&hook)
+            .current_dir(&project.path)
+            .env("GIT_DIR", project.path.join(".git"))
+            .env("GIT_INDEX_FILE", scratch_index.path())
+            .status()
+            .with_context(|| format!("failed to spawn pre-commit hook at {}", hook.display()))?;
+        if !status.success() {
+            bail!("pre-commit hook rejected the commit (exit status: {status})");
+        }
+        Ok(())
+    }
+
+    /// Runs `prepare-commit-msg` followed by `commit-msg` against `message`, returning
+    /// the (possibly hook-rewritten) message. Bails if `commit-msg` rejects it.
+    pub(super) fn run_commit_msg_hooks(project: &Project, message: &str) -> anyhow::Result<String> {
+        let mut msg_file = tempfile::NamedTempFile::new_in(project.gb_dir())
+            .context("failed to create scratch commit-message file")?;
+        msg_file.write_all(message.as_bytes())?;
+        msg_file.flush()?;
+
+        if let Some(hook) = find_hook(project, "prepare-commit-msg") {
+            let status = Command::new(&hook)
+                .arg(msg_file.path())
+                .arg("message")
+                .current_dir(&project.path)
+                .env("GIT_DIR", project.path.join(".git"))
+                .status()
+                .with_context(|| {
+                    format!("failed to spawn prepare-commit-msg hook at {}", hook.display())
+                })?;
+            if !status.success() {
+                bail!("prepare-commit-msg hook rejected the commit (exit status: {status})");
+            }
+        }
+
+        if let Some(hook) = find_hook(project, "commit-msg") {
+            let status = Command::new(&hook)
+                .arg(msg_file.path())
+                .current_dir(&project.path)
+                .env("GIT_DIR", project.path.join(".git"))
+                .status()
+                .with_context(|| format!("failed to spawn commit-msg hook at {}", hook.display()))?;
+            if !status.success() {
+                bail!("commit-msg hook rejected the commit (exit status: {status})");
+            }
+        }
+
+        Ok(std::fs::read_to_string(msg_file.path())?)
+    }
+
+    /// Runs `.git/hooks/post-commit`, ignoring its exit status (the commit has already
+    /// landed by the time this runs, same as plain `git commit`).
+    pub(super) fn run_post_commit(project: &Project) -> anyhow::Result<()> {
+        let Some(hook) = find_hook(project, "post-commit") else {
+            return Ok(());
+        };
+        Command::new(&hook)
+            .current_dir(&project.path)
+            .env("GIT_DIR", project.path.join(".git"))
+            .status()
+            .with_context(|| format!("failed to spawn post-commit hook at {}", hook.display()))?;
+        Ok(())
+    }
+
+    fn find_hook(project: &Project, name: &str) -> Option<PathBuf> {
+        let path = project.path.join(".git/hooks").join(name);
+        is_executable(&path).then_some(path)
+    }
+
+    #[cfg(unix)]
+    fn is_executable(path: &Path) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(path)
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    fn is_executable(path: &Path) -> bool {
+        path.is_file()
+    }
+}
+
+/// Cryptographic signing of commits created through `commit`, honoring the same
+/// config Git itself reads (`commit.gpgsign`, `gpg.format`, `user.signingkey`,
+/// `gpg.ssh.program`) unless overridden by `--gpg-sign`/`--no-gpg-sign`.
+pub(crate) mod signing {
+    use super::*;
+    use but_workspace::commit_engine::Signer;
+
+    /// `--gpg-sign[=keyid]` / `--no-gpg-sign` as given on the command line. `None`
+    /// means neither flag was passed, so `commit.gpgsign` decides.
+    pub(crate) enum Override {
+        /// `--gpg-sign` (no keyid) or `--gpg-sign=<keyid>`.
+        Enable(Option<String>),
+        /// `--no-gpg-sign`.
+        Disable,
+    }
+
+    /// Resolves the `Signer` that should be used for this invocation, or `None` if
+    /// signing isn't requested.
+    pub(super) fn resolve(
+        repo: &gix::Repository,
+        cli_override: Option<Override>,
+    ) -> anyhow::Result<Option<Signer>> {
+        let config = repo.config_snapshot();
+        let config_gpgsign = config.boolean("commit.gpgsign").unwrap_or(false);
+        let config_signingkey = config.string("user.signingkey").map(|s| s.to_string());
+
+        let Some(keyid) = decide_signing(cli_override.as_ref(), config_gpgsign, config_signingkey.as_deref())?
+        else {
+            return Ok(None);
+        };
+
+        let format = config
+            .string("gpg.format")
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "openpgp".to_string());
+
+        Ok(Some(match format.as_str() {
+            "ssh" => {
+                let program = config
+                    .string("gpg.ssh.program")
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "ssh-keygen".to_string());
+                Signer::Ssh {
+                    program: program.into(),
+                    keyid,
+                }
+            }
+            _ => {
+                let program = config
+                    .string("gpg.program")
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "gpg".to_string());
+                Signer::Gpg {
+                    program: program.into(),
+                    keyid,
+                }
+            }
+        }))
+    }
+
+    /// Whether signing is enabled for this commit and, if so, which key id to use -
+    /// pulled out of `resolve` so the CLI-vs-config precedence is unit-testable
+    /// without a real repository. `--gpg-sign`/`--no-gpg-sign` always win over
+    /// `commit.gpgsign`; an explicit `--gpg-sign=<keyid>` always wins over
+    /// `user.signingkey`.
+    fn decide_signing(
+        cli_override: Option<&Override>,
+        config_gpgsign: bool,
+        config_signingkey: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        let (enabled, keyid) = match cli_override {
+            Some(Override::Disable) => (false, None),
+            Some(Override::Enable(keyid)) => (true, keyid.clone()),
+            None => (config_gpgsign, None),
+        };
+        if !enabled {
+            return Ok(None);
+        }
+        keyid
+            .or_else(|| config_signingkey.map(ToOwned::to_owned))
+            .context("commit signing is enabled but no `user.signingkey` is configured")
+            .map(Some)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn no_override_falls_back_to_config() {
+            assert_eq!(
+                decide_signing(None, false, None).unwrap(),
+                None,
+                "disabled in config, no override -> no signing"
+            );
+            assert_eq!(
+                decide_signing(None, true, Some("ABC123")).unwrap(),
+                Some("ABC123".to_string())
+            );
+        }
+
+        #[test]
+        fn no_gpg_sign_wins_even_if_config_enables_it() {
+            assert_eq!(decide_signing(Some(&Override::Disable), true, Some("ABC123")).unwrap(), None);
+        }
+
+        #[test]
+        fn gpg_sign_wins_even_if_config_disables_it() {
+            assert_eq!(
+                decide_signing(Some(&Override::Enable(None)), false, Some("ABC123")).unwrap(),
+                Some("ABC123".to_string())
+            );
+        }
+
+        #[test]
+        fn explicit_keyid_overrides_config_signingkey() {
+            assert_eq!(
+                decide_signing(
+                    Some(&Override::Enable(Some("OVERRIDE".to_string()))),
+                    false,
+                    Some("ABC123")
+                )
+                .unwrap(),
+                Some("OVERRIDE".to_string())
+            );
+        }
+
+        #[test]
+        fn enabled_without_any_keyid_is_an_error() {
+            assert!(decide_signing(Some(&Override::Enable(None)), false, None).is_err());
+        }
+    }
+}
+
+/// Serializes a stack segment into a self-contained, re-importable patch bundle: a
+/// cover letter plus one record per commit, a `mergepoint` record marking the current
+/// base and tip, and a `snapshot` record that lets a fresh clone reconstruct the full
+/// history without a live remote. Every export walks the whole range from the merge-base
+/// to the tip; a previously written `mergepoint` is not read. Records live under
+/// `refs/gitbutler/<topic>/...` rather than loose `.patch` files, so repeated exports of
+/// the same branch accumulate under one topic id.
+#[allow(clippy::too_many_arguments)]
+pub fn export_patch_bundle(
+    repo: gix::Repository,
+    project: Project,
+    stack_segment_ref: &str,
+    topic: Option<&str>,
+    gpg_sign: Option<signing::Override>,
+) -> anyhow::Result<()> {
+    let full_name = normalize_stack_segment_ref(stack_segment_ref)?;
+    let stack = VirtualBranchesHandle::new(project.gb_dir())
+        .list_stacks_in_workspace()?
+        .into_iter()
+        .find(|s| s.heads(false).contains(&stack_segment_ref.to_string()))
+        .ok_or_else(|| anyhow::anyhow!("no stack segment named '{stack_segment_ref}'"))?;
+
+    let head = repo
+        .find_reference(full_name.as_ref())?
+        .peel_to_commit()?
+        .id;
+    let base = stack.merge_base(&repo)?;
+    let topic = topic
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| format!("{}-{}", stack_segment_ref, &stack.id.to_string()[..8]));
+    let signer = signing::resolve(&repo, gpg_sign)?;
+
+    let mut commits = Vec::new();
+    let mut cursor = head;
+    while cursor != base {
+        let commit = repo.find_commit(cursor)?;
+        commits.push(patch_bundle::Record::for_commit(&repo, &commit, &topic, signer.as_ref())?);
+        cursor = commit
+            .parent_ids()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("reached a root commit before reaching the merge-base"))?
+            .detach();
+    }
+    commits.reverse();
+
+    let mergepoint = patch_bundle::Record::mergepoint(&repo, &topic, base, head, signer.as_ref())?;
+    let snapshot = patch_bundle::Record::snapshot(&repo, &topic, base, head, signer.as_ref())?;
+    let cover_letter =
+        patch_bundle::Record::cover_letter(&repo, &topic, base, head, commits.len(), signer.as_ref())?;
+
+    patch_bundle::write_records(&repo, &cover_letter, &commits, &mergepoint, &snapshot)?;
+
+    debug_print(serde_json::json!({
+        "topic": topic,
+        "base": base.to_string(),
+        "head": head.to_string(),
+        "commits": commits.len(),
+    }))
+}
+
+mod patch_bundle {
+    use super::*;
+
+    pub(super) enum Kind {
+        CoverLetter,
+        Commit,
+        MergePoint,
+        Snapshot,
+    }
+
+    /// One entry in a patch bundle: the cover letter, a commit's patch, or one of the
+    /// bookkeeping records (`mergepoint`, `snapshot`) that make incremental, offline
+    /// re-import possible.
+    pub(super) struct Record {
+        pub kind: Kind,
+        pub topic: String,
+        pub base: gix::ObjectId,
+        pub head: gix::ObjectId,
+        pub author: gix::actor::Signature,
+        /// Id of the blob actually written into the repository's object database for
+        /// this record's patch/payload (plus its trailing signature, if any) - what
+        /// the record's ref is pointed at, so resolving the ref always yields real,
+        /// fetchable content rather than a hash nothing ever wrote.
+        pub content_hash: gix::ObjectId,
+        pub signature: Option<String>,
+    }
+
+    impl Record {
+        pub fn for_commit(
+            repo: &gix::Repository,
+            commit: &gix::Commit<'_>,
+            topic: &str,
+            signer: Option<&but_workspace::commit_engine::Signer>,
+        ) -> anyhow::Result<Self> {
+            let parent = commit
+                .parent_ids()
+                .next()
+                .map(|id| id.detach())
+                .unwrap_or_else(|| repo.object_hash().empty_tree());
+            let patch = but_core::diff::commit_patch(repo, commit.id)?;
+            let signature = signer.map(|s| s.sign(&patch)).transpose()?;
+            let content_hash = write_payload(repo, &patch, signature.as_deref())?;
+            Ok(Self {
+                kind: Kind::Commit,
+                topic: topic.to_owned(),
+                base: parent,
+                head: commit.id,
+                author: commit.author()?.to_owned(),
+                content_hash,
+                signature,
+            })
+        }
+
+        pub fn cover_letter(
+            repo: &gix::Repository,
+            topic: &str,
+            base: gix::ObjectId,
+            head: gix::ObjectId,
+            commit_count: usize,
+            signer: Option<&but_workspace::commit_engine::Signer>,
+        ) -> anyhow::Result<Self> {
+            let payload = format!("cover-letter {topic}\n\n{commit_count} commit(s), {base}..{head}\n");
+            Self::bookkeeping(repo, Kind::CoverLetter, topic, base, head, payload, signer)
+        }
+
+        pub fn mergepoint(
+            repo: &gix::Repository,
+            topic: &str,
+            base: gix::ObjectId,
+            head: gix::ObjectId,
+            signer: Option<&but_workspace::commit_engine::Signer>,
+        ) -> anyhow::Result<Self> {
+            let payload = format!("mergepoint {topic} {base} {head}");
+            Self::bookkeeping(repo, Kind::MergePoint, topic, base, head, payload, signer)
+        }
+
+        pub fn snapshot(
+            repo: &gix::Repository,
+            topic: &str,
+            base: gix::ObjectId,
+            head: gix::ObjectId,
+            signer: Option<&but_workspace::commit_engine::Signer>,
+        ) -> anyhow::Result<Self> {
+            let payload = but_core::diff::commit_range_patch(repo, base, head)?;
+            Self::bookkeeping(
+                repo,
+                Kind::Snapshot,
+                topic,
+                base,
+                head,
+                String::from_utf8_lossy(&payload).into_owned(),
+                signer,
+            )
+        }
+
+        fn bookkeeping(
+            repo: &gix::Repository,
+            kind: Kind,
+            topic: &str,
+            base: gix::ObjectId,
+            head: gix::ObjectId,
+            payload: String,
+            signer: Option<&but_workspace::commit_engine::Signer>,
+        ) -> anyhow::Result<Self> {
+            let signature = signer.map(|s| s.sign(payload.as_bytes())).transpose()?;
+            let content_hash = write_payload(repo, payload.as_bytes(), signature.as_deref())?;
+            Ok(Self {
+                content_hash,
+                signature,
+                author: gix::actor::Signature::empty(),
+                kind,
+                topic: topic.to_owned(),
+                base,
+                head,
+            })
+        }
+
+        fn ref_name(&self, index: usize) -> anyhow::Result<gix::refs::FullName> {
+            let suffix = match self.kind {
+                Kind::CoverLetter => "cover-letter".to_string(),
+                Kind::Commit => format!("patches/{index}"),
+                Kind::MergePoint => "mergepoint".to_string(),
+                Kind::Snapshot => "snapshot".to_string(),
+            };
+            Ok(gix::refs::FullName::try_from(format!(
+                "refs/gitbutler/{}/{suffix}",
+                self.topic
+            ))?)
+        }
+    }
+
+    /// Writes `payload` (with `signature` appended as a trailer block, if present) as a
+    /// blob into the repository's object database, returning its id.
+    fn write_payload(repo: &gix::Repository, payload: &[u8], signature: Option<&str>) -> anyhow::Result<gix::ObjectId> {
+        let mut bytes = payload.to_vec();
+        if let Some(signature) = signature {
+            bytes.extend_from_slice(b"\n-----BEGIN GITBUTLER PATCH SIGNATURE-----\n");
+            bytes.extend_from_slice(signature.as_bytes());
+            bytes.extend_from_slice(b"\n-----END GITBUTLER PATCH SIGNATURE-----\n");
+        }
+        Ok(repo.write_blob(&bytes)?.detach())
+    }
+
+    /// Writes every record of the bundle - the cover letter, each commit's patch, the
+    /// mergepoint and the snapshot - under `refs/gitbutler/<topic>/...`, replacing
+    /// whatever the topic's refs previously pointed at so a second export of the same
+    /// branch is additive rather than duplicative.
+    pub(super) fn write_records(
+        repo: &gix::Repository,
+        cover_letter: &Record,
+        commits: &[Record],
+        mergepoint: &Record,
+        snapshot: &Record,
+    ) -> anyhow::Result<()> {
+        let mut edits = vec![ref_edit(repo, cover_letter, 0)?];
+        for (index, record) in commits.iter().enumerate() {
+            edits.push(ref_edit(repo, record, index)?);
+        }
+        edits.push(ref_edit(repo, mergepoint, 0)?);
+        edits.push(ref_edit(repo, snapshot, 0)?);
+        repo.edit_references(edits)?;
+        Ok(())
+    }
+
+    fn ref_edit(
+        repo: &gix::Repository,
+        record: &Record,
+        index: usize,
+    ) -> anyhow::Result<gix::refs::transaction::RefEdit> {
+        Ok(gix::refs::transaction::RefEdit {
+            change: gix::refs::transaction::Change::Update {
+                log: Default::default(),
+                expected: gix::refs::transaction::PreviousValue::Any,
+                new: gix::refs::Target::Object(record.content_hash),
+            },
+            name: record.ref_name(index)?,
+            deref: false,
+        })
+    }
+}
+
+/// Parses a standard unified diff (as produced by `git diff`/`git apply`-compatible
+/// tooling) directly into `DiffSpec`/`HunkHeader` values, so `commit --patch` can take
+/// an externally produced or hand-edited patch instead of numeric hunk indices.
+mod unified_diff {
+    use super::*;
+
+    /// Parses `text` into one `DiffSpec` per file section, validating that every
+    /// hunk's old-side context still matches the current worktree content before
+    /// accepting it - the same robustness `git apply` gives you, surfaced as a clear
+    /// error naming the offending hunk rather than a silent mis-commit.
+    pub(super) fn parse(repo: &gix::Repository, text: &str) -> anyhow::Result<Vec<DiffSpec>> {
+        let sections = split_sections(text)?;
+        let mut specs = Vec::with_capacity(sections.len());
+
+        for section in sections {
+            let (previous_path, current_path) =
+                resolve_section_paths(section.previous_path, section.path)?;
+
+            let mut hunk_headers = Vec::with_capacity(section.hunks.len());
+            for (hunk_line, header, body) in &section.hunks {
+                verify_old_side_context(repo, &current_path, previous_path.as_ref(), header, body)
+                    .with_context(|| format!("hunk @@ {hunk_line} @@ no longer matches the tree"))?;
+                hunk_headers.push(*header);
+            }
+
+            specs.push(DiffSpec {
+                previous_path,
+                path: current_path,
+                hunk_headers,
+            });
+        }
+
+        Ok(specs)
+    }
+
+    /// Resolves a section's raw `--- `/`+++ ` path strings into the `(previous_path,
+    /// path)` pair `DiffSpec` expects: `/dev/null` on the old side means the file is
+    /// being created (no previous path), and - mirroring that - `/dev/null` on the new
+    /// side means it's being deleted, so there's no new-side path to resolve and we
+    /// fall back to the old one instead.
+    fn resolve_section_paths(
+        previous_path: &str,
+        path: &str,
+    ) -> anyhow::Result<(Option<gix::path::RelaPath>, gix::path::RelaPath)> {
+        let previous_path = (previous_path != "/dev/null")
+            .then(|| path_to_rela_path(Path::new(previous_path)))
+            .transpose()?;
+        let current_path = if path == "/dev/null" {
+            previous_path
+                .clone()
+                .context("a deletion hunk ('+++ /dev/null') must have a real '--- ' path")?
+        } else {
+            path_to_rela_path(Path::new(path))?
+        };
+        Ok((previous_path, current_path))
+    }
+
+    /// One `--- `/`+++ ` file section of a unified diff: its (still raw, un-resolved)
+    /// path strings and its hunks, each with the original `@@ ... @@` line (for error
+    /// messages), its parsed `HunkHeader`, and its body lines.
+    struct Section<'a> {
+        previous_path: &'a str,
+        path: &'a str,
+        hunks: Vec<(&'a str, HunkHeader, Vec<&'a str>)>,
+    }
+
+    /// Splits `text` into per-file sections and, within each, per-hunk header/body
+    /// pairs - everything `parse` does except the worktree-context check, so this part
+    /// is unit-testable without a repository.
+    fn split_sections(text: &str) -> anyhow::Result<Vec<Section<'_>>> {
+        let mut sections = Vec::new();
+        let mut lines = text.lines().peekable();
+
+        while let Some(line) = lines.next() {
+            let Some(rest) = line.strip_prefix("--- ") else {
+                continue;
+            };
+            let Some(plus_line) = lines.next() else {
+                bail!("patch ends right after a '--- ' file header");
+            };
+            let Some(new_rest) = plus_line.strip_prefix("+++ ") else {
+                bail!("expected a '+++ ' line after '--- {rest}'");
+            };
+
+            let previous_path = strip_diff_prefix(rest);
+            let path = strip_diff_prefix(new_rest);
+
+            let mut hunks = Vec::new();
+            while let Some(&hunk_line) = lines.peek() {
+                if !hunk_line.starts_with("@@ ") {
+                    break;
+                }
+                lines.next();
+                let header = parse_hunk_header(hunk_line)
+                    .with_context(|| format!("malformed hunk header: {hunk_line}"))?;
+
+                let mut hunk_body = Vec::new();
+                while let Some(&body_line) = lines.peek() {
+                    if body_line.starts_with("@@ ") || body_line.starts_with("diff --git ") {
+                        break;
+                    }
+                    if body_line.starts_with("--- ") {
+                        // Only a new file header if '+++ ' immediately follows - a
+                        // removed line whose content happens to start with '-- '
+                        // (common in SQL/Lua/Haskell comments) also matches '--- '
+                        // once prefixed with its '-' diff marker, but isn't a
+                        // section boundary.
+                        let mut lookahead = lines.clone();
+                        lookahead.next();
+                        if lookahead.peek().is_some_and(|next| next.starts_with("+++ ")) {
+                            break;
+                        }
+                    }
+                    lines.next();
+                    // `\ No newline at end of file` etc. - not a real content line,
+                    // so it carries no position in the old/new file and must not be
+                    // compared against the worktree by `first_context_mismatch`.
+                    if !body_line.starts_with('\\') {
+                        hunk_body.push(body_line);
+                    }
+                }
+
+                hunks.push((hunk_line, header, hunk_body));
+            }
+
+            sections.push(Section {
+                previous_path,
+                path,
+                hunks,
+            });
+        }
+
+        if sections.is_empty() {
+            bail!("patch didn't contain any recognizable '--- '/'+++ ' file sections");
+        }
+        Ok(sections)
+    }
+
+    /// Extracts the hunk headers for `path`'s file section out of a full unified diff
+    /// such as `commit_patch`'s output. Unlike `parse`, this doesn't validate anything
+    /// against the worktree - callers use it to inspect a historical commit's patch,
+    /// not to stage a new one.
+    pub(super) fn hunks_for_path(
+        text: &str,
+        path: &gix::path::RelaPath,
+        previous_path: Option<&gix::path::RelaPath>,
+    ) -> Vec<HunkHeader> {
+        let wanted_old = previous_path.map(|p| p.as_str()).unwrap_or(path.as_str());
+        let wanted_new = path.as_str();
+        let mut headers = Vec::new();
+        let mut in_wanted_file = false;
+        let mut lines = text.lines().peekable();
+
+        while let Some(line) = lines.next() {
+            if line.starts_with("diff --git ") {
+                in_wanted_file = false;
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("--- ") {
+                let Some(&plus_line) = lines.peek() else {
+                    break;
+                };
+                if let Some(new_rest) = plus_line.strip_prefix("+++ ") {
+                    lines.next();
+                    let old_path = strip_diff_prefix(rest);
+                    let new_path = strip_diff_prefix(new_rest);
+                    in_wanted_file = old_path == wanted_old && new_path == wanted_new;
+                }
+                continue;
+            }
+            if in_wanted_file && line.starts_with("@@ ") {
+                if let Ok(header) = parse_hunk_header(line) {
+                    headers.push(header);
+                }
+            }
+        }
+        headers
+    }
+
+    fn strip_diff_prefix(path: &str) -> &str {
+        let path = path.split('\t').next().unwrap_or(path);
+        path.strip_prefix("a/")
+            .or_else(|| path.strip_prefix("b/"))
+            .unwrap_or(path)
+    }
+
+    fn parse_hunk_header(line: &str) -> anyhow::Result<HunkHeader> {
+        let inner = line
+            .strip_prefix("@@ ")
+            .and_then(|s| s.split(" @@").next())
+            .context("missing '@@ ... @@' markers")?;
+        let (old, new) = inner
+            .split_once(' ')
+            .context("expected both an old-file and new-file range")?;
+        let (old_start, old_lines) = parse_range(old.trim_start_matches('-'))?;
+        let (new_start, new_lines) = parse_range(new.trim_start_matches('+'))?;
+        Ok(HunkHeader {
+            old_start,
+            old_lines,
+            new_start,
+            new_lines,
+        })
+    }
+
+    fn parse_range(range: &str) -> anyhow::Result<(u32, u32)> {
+        let (start, len) = match range.split_once(',') {
+            Some((start, len)) => (start.parse()?, len.parse()?),
+            None => (range.parse()?, 1),
+        };
+        Ok((start, len))
+    }
+
+    /// Makes sure the hunk's old-side (context and `-`) lines still match what's
+    /// currently on disk in the worktree, so a drifted patch is rejected instead of
+    /// silently applied against the wrong base.
+    fn verify_old_side_context(
+        repo: &gix::Repository,
+        path: &gix::path::RelaPath,
+        previous_path: Option<&gix::path::RelaPath>,
+        header: &HunkHeader,
+        body: &[&str],
+    ) -> anyhow::Result<()> {
+        let old_source_path = previous_path.unwrap_or(path);
+        let current = but_core::diff::blob_at_worktree_path(repo, old_source_path)?;
+        let current_lines: Vec<&str> = current.lines().collect();
+
+        if let Some((line_number, expected, actual)) = first_context_mismatch(&current_lines, header, body) {
+            bail!("context mismatch at old-file line {line_number}: expected {expected:?}, found {actual:?}");
+        }
+        Ok(())
+    }
+
+    /// The pure comparison behind `verify_old_side_context`: does any context/`-` line
+    /// in `body` fail to match `current_lines` at the position `header` claims? Split
+    /// out so the matching logic is unit-testable without a real worktree.
+    fn first_context_mismatch<'a>(
+        current_lines: &[&'a str],
+        header: &HunkHeader,
+        body: &[&'a str],
+    ) -> Option<(usize, &'a str, &'a str)> {
+        let mut offset = 0u32;
+        for line in body {
+            if line.starts_with('+') || line.starts_with('\\') {
+                continue;
+            }
+            let expected = line.get(1..).unwrap_or_default();
+            let index = (header.old_start.saturating_sub(1) + offset) as usize;
+            let actual = current_lines.get(index).copied().unwrap_or_default();
+            if actual != expected {
+                return Some((index + 1, expected, actual));
+            }
+            offset += 1;
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn header(old_start: u32, old_lines: u32, new_start: u32, new_lines: u32) -> HunkHeader {
+            HunkHeader {
+                old_start,
+                old_lines,
+                new_start,
+                new_lines,
+            }
+        }
+
+        #[test]
+        fn parses_single_line_ranges_as_length_one() {
+            let h = parse_hunk_header("@@ -5 +5 @@").unwrap();
+            assert_eq!((h.old_start, h.old_lines), (5, 1));
+            assert_eq!((h.new_start, h.new_lines), (5, 1));
+        }
+
+        #[test]
+        fn parses_ranges_with_explicit_length() {
+            let h = parse_hunk_header("@@ -10,3 +12,5 @@ fn foo() {").unwrap();
+            assert_eq!((h.old_start, h.old_lines), (10, 3));
+            assert_eq!((h.new_start, h.new_lines), (12, 5));
+        }
+
+        #[test]
+        fn rejects_a_line_without_hunk_markers() {
+            assert!(parse_hunk_header("not a hunk header").is_err());
+        }
+
+        #[test]
+        fn parse_range_defaults_length_to_one() {
+            assert_eq!(parse_range("42").unwrap(), (42, 1));
+            assert_eq!(parse_range("42,7").unwrap(), (42, 7));
+        }
+
+        #[test]
+        fn matching_context_lines_pass() {
+            let current = vec!["one", "two", "three"];
+            let body = vec![" two", "-three", "+THREE"];
+            assert_eq!(first_context_mismatch(&current, &header(2, 2, 2, 2), &body), None);
+        }
+
+        #[test]
+        fn drifted_context_line_is_reported() {
+            let current = vec!["one", "TWO", "three"];
+            let body = vec![" two"];
+            assert_eq!(
+                first_context_mismatch(&current, &header(2, 1, 2, 1), &body),
+                Some((2, "two", "TWO"))
+            );
+        }
+
+        #[test]
+        fn resolve_section_paths_handles_creation() {
+            let (previous, current) = resolve_section_paths("/dev/null", "new.txt").unwrap();
+            assert_eq!(previous, None);
+            assert_eq!(current.as_str(), "new.txt");
+        }
+
+        #[test]
+        fn resolve_section_paths_handles_deletion() {
+            let (previous, current) = resolve_section_paths("old.txt", "/dev/null").unwrap();
+            assert_eq!(previous.as_ref().map(|p| p.as_str()), Some("old.txt"));
+            assert_eq!(current.as_str(), "old.txt");
+        }
+
+        #[test]
+        fn resolve_section_paths_handles_an_ordinary_modification() {
+            let (previous, current) = resolve_section_paths("f.txt", "f.txt").unwrap();
+            assert_eq!(previous.as_ref().map(|p| p.as_str()), Some("f.txt"));
+            assert_eq!(current.as_str(), "f.txt");
+        }
+
+        #[test]
+        fn no_newline_marker_is_not_treated_as_a_context_line() {
+            let current = vec!["one", "two"];
+            let body = vec![" one", "-two", "\\ No newline at end of file", "+two!"];
+            assert_eq!(first_context_mismatch(&current, &header(1, 2, 1, 2), &body), None);
+        }
+
+        #[test]
+        fn split_sections_drops_the_no_newline_marker_from_the_hunk_body() {
+            let patch = "--- a/f.txt\n\
+                 +++ b/f.txt\n\
+                 @@ -1,1 +1,1 @@\n\
+                 -old\n\
+                 \\ No newline at end of file\n\
+                 +new\n\
+                 \\ No newline at end of file\n";
+            let sections = split_sections(patch).unwrap();
+            assert_eq!(sections[0].hunks[0].2, vec!["-old", "+new"]);
+        }
+
+        #[test]
+        fn hunks_for_path_skips_other_files_diff_git_headers() {
+            let patch = "diff --git a/a.txt b/a.txt\n\
+                 index 111..222 100644\n\
+                 --- a/a.txt\n\
+                 +++ b/a.txt\n\
+                 @@ -1,1 +1,1 @@\n\
+                 -old\n\
+                 +new\n\
+                 diff --git a/b.txt b/b.txt\n\
+                 index 333..444 100644\n\
+                 --- a/b.txt\n\
+                 +++ b/b.txt\n\
+                 @@ -1,1 +1,1 @@\n\
+                 -old-b\n\
+                 +new-b\n";
+
+            let a = path_to_rela_path(Path::new("a.txt")).unwrap();
+            let headers = hunks_for_path(patch, &a, None);
+            assert_eq!(headers.len(), 1, "must not swallow b.txt's header into a.txt's hunk");
+            assert_eq!((headers[0].old_start, headers[0].old_lines), (1, 1));
+        }
+
+        #[test]
+        fn split_sections_does_not_swallow_the_next_files_diff_git_header() {
+            let patch = "diff --git a/a.txt b/a.txt\n\
+                 index 111..222 100644\n\
+                 --- a/a.txt\n\
+                 +++ b/a.txt\n\
+                 @@ -1,1 +1,1 @@\n\
+                 -old\n\
+                 +new\n\
+                 diff --git a/b.txt b/b.txt\n\
+                 index 333..444 100644\n\
+                 --- a/b.txt\n\
+                 +++ b/b.txt\n\
+                 @@ -1,1 +1,1 @@\n\
+                 -old-b\n\
+                 +new-b\n";
+
+            let sections = split_sections(patch).unwrap();
+            assert_eq!(sections.len(), 2, "each file must become its own section");
+            assert_eq!(sections[0].path, "a.txt");
+            assert_eq!(sections[0].hunks.len(), 1);
+            assert_eq!(sections[0].hunks[0].2, vec!["-old", "+new"]);
+            assert_eq!(sections[1].path, "b.txt");
+        }
+
+        #[test]
+        fn split_sections_keeps_a_removed_line_that_looks_like_a_file_header() {
+            // A removed `-- ` SQL comment, once prefixed with the diff's own `-`
+            // marker, reads as `--- a helpful comment` - it must not be mistaken for
+            // the next file's `--- `/`+++ ` header pair.
+            let patch = "--- a/query.sql\n\
+                 +++ b/query.sql\n\
+                 @@ -1,2 +1,2 @@\n\
+                 --- a helpful comment\n\
+                 -select 1;\n\
+                 +select 2;\n";
+
+            let sections = split_sections(patch).unwrap();
+            assert_eq!(sections.len(), 1);
+            assert_eq!(sections[0].hunks.len(), 1);
+            assert_eq!(
+                sections[0].hunks[0].2,
+                vec!["--- a helpful comment", "-select 1;", "+select 2;"]
+            );
+        }
+    }
+}